@@ -0,0 +1,386 @@
+// Cuneos crypto core: versioned payload encryption, purpose-bound key derivation, X3DH agreement combining and
+// signature checks shared by the node and constrained clients. Only `alloc` is required; the `std`
+// feature adds the OS random number generator.
+#![no_std]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use chacha20poly1305::{
+    aead::{Aead, KeyInit, Payload},
+    XChaCha20Poly1305, XNonce,
+};
+use curve25519_dalek::{
+    constants::ED25519_BASEPOINT_POINT,
+    edwards::{CompressedEdwardsY, EdwardsPoint},
+    scalar::Scalar,
+    traits::{IsIdentity, VartimeMultiscalarMul},
+};
+use hkdf::Hkdf;
+use hmac::Hmac;
+use rand_core::CryptoRngCore;
+use sha2::Sha512;
+use sha3::{Digest, Sha3_256};
+use x25519_dalek::SharedSecret;
+use zeroize::Zeroizing;
+
+pub use hmac::Mac;
+
+pub type HmacSha3 = Hmac<Sha3_256>;
+
+const NONCE_LEN: usize = 24;
+
+// KeyPurpose: Context labels that separate the keys derived from one X25519 shared secret
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum KeyPurpose {
+    ProfileKeyWrap,
+    Message,
+    Photo,
+    Voice,
+    MessageAuth,
+    SealedSender,
+    SafetyAlert,
+    SealedView,
+}
+
+impl KeyPurpose {
+    pub fn label(&self) -> &'static [u8] {
+        match self {
+            KeyPurpose::ProfileKeyWrap => b"cuneos/profile-key-wrap/v1",
+            KeyPurpose::Message => b"cuneos/message/v1",
+            KeyPurpose::Photo => b"cuneos/photo/v1",
+            KeyPurpose::Voice => b"cuneos/voice/v1",
+            KeyPurpose::MessageAuth => b"cuneos/message-auth/v1",
+            KeyPurpose::SealedSender => b"cuneos/sealed-sender/v1",
+            KeyPurpose::SafetyAlert => b"cuneos/safety-alert/v1",
+            KeyPurpose::SealedView => b"cuneos/sealed-view/v1",
+        }
+    }
+}
+
+// derive_purpose_key: HKDF-SHA3-256 over the raw DH output, so no two purposes ever share a key
+pub fn derive_purpose_key(shared_secret: &[u8; 32], purpose: KeyPurpose) -> Zeroizing<[u8; 32]> {
+    let hkdf = Hkdf::<Sha3_256>::new(Some(b"cuneos-x25519"), shared_secret);
+    let mut key = Zeroizing::new([0u8; 32]);
+    hkdf.expand(purpose.label(), key.as_mut())
+        .expect("32 bytes is a valid HKDF output length");
+    key
+}
+
+// purpose_mac: HMAC-SHA3-256 keyed by the purpose key derived from a shared secret
+pub fn purpose_mac(shared_secret: &[u8; 32], purpose: KeyPurpose) -> HmacSha3 {
+    let key = derive_purpose_key(shared_secret, purpose);
+    <HmacSha3 as Mac>::new_from_slice(key.as_slice()).expect("HMAC accepts any key length")
+}
+
+// seal_payload_with_rng: XChaCha20-Poly1305 encryption under a random 24-byte nonce, binding `aad` to the ciphertext.
+// This is the unversioned format from before envelopes; new data is sealed with seal_envelope.
+pub fn seal_payload_with_rng(rng: &mut impl CryptoRngCore, key: &[u8; 32], plaintext: &[u8], aad: &[u8]) -> Vec<u8> {
+    let cipher = XChaCha20Poly1305::new(key.into());
+    let mut nonce_bytes = [0u8; NONCE_LEN];
+    rng.fill_bytes(&mut nonce_bytes);
+    let ciphertext = cipher.encrypt(XNonce::from_slice(&nonce_bytes), Payload { msg: plaintext, aad })
+        .expect("Failed to encrypt payload");
+    let mut sealed = nonce_bytes.to_vec();
+    sealed.extend(ciphertext);
+    sealed
+}
+
+// seal_payload: seal_payload_with_rng drawing the nonce from the operating system
+#[cfg(feature = "std")]
+pub fn seal_payload(key: &[u8; 32], plaintext: &[u8], aad: &[u8]) -> Vec<u8> {
+    seal_payload_with_rng(&mut rand_core::OsRng, key, plaintext, aad)
+}
+
+// open_payload: Reverses seal_payload; fails if the key, ciphertext or associated data differ
+pub fn open_payload(key: &[u8; 32], sealed: &[u8], aad: &[u8]) -> Option<Zeroizing<Vec<u8>>> {
+    if sealed.len() < NONCE_LEN {
+        return None;
+    }
+    let cipher = XChaCha20Poly1305::new(key.into());
+    let (nonce_bytes, ciphertext) = sealed.split_at(NONCE_LEN);
+    cipher.decrypt(XNonce::from_slice(nonce_bytes), Payload { msg: ciphertext, aad }).ok().map(Zeroizing::new)
+}
+
+// CipherSuite: AEAD algorithms a versioned envelope can name. The id is written into every envelope, so an id is
+// never reused; a new algorithm gets a new id and the old ones keep decrypting.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CipherSuite {
+    XChaCha20Poly1305 = 1,
+}
+
+impl CipherSuite {
+    // Suites this build can seal with, most preferred first
+    pub const SUPPORTED: [CipherSuite; 1] = [CipherSuite::XChaCha20Poly1305];
+    pub const LATEST: CipherSuite = CipherSuite::SUPPORTED[0];
+
+    pub fn id(self) -> u8 {
+        self as u8
+    }
+
+    pub fn from_id(id: u8) -> Option<CipherSuite> {
+        CipherSuite::SUPPORTED.into_iter().find(|suite| suite.id() == id)
+    }
+
+    fn nonce_len(self) -> usize {
+        match self {
+            CipherSuite::XChaCha20Poly1305 => NONCE_LEN,
+        }
+    }
+}
+
+// negotiate_suite: The first of our suites, in our order of preference, that the peer also lists. None means the
+// peer only reads unversioned payloads, which seal_payload still writes.
+pub fn negotiate_suite(peer_suite_ids: &[u8]) -> Option<CipherSuite> {
+    CipherSuite::SUPPORTED.into_iter().find(|suite| peer_suite_ids.contains(&suite.id()))
+}
+
+const ENVELOPE_MAGIC: [u8; 3] = *b"CNV";
+pub const KEY_ID_LEN: usize = 8;
+
+// key_id: Short fingerprint of a symmetric key, so a reader holding several keys knows which one an envelope needs
+pub fn key_id(key: &[u8; 32]) -> [u8; KEY_ID_LEN] {
+    let digest = Sha3_256::new().chain_update(b"cuneos/key-id/v1").chain_update(key).finalize();
+    let mut id = [0u8; KEY_ID_LEN];
+    id.copy_from_slice(&digest[..KEY_ID_LEN]);
+    id
+}
+
+// EnvelopeHeader: What precedes the ciphertext in a versioned envelope: "CNV", the suite id, the key id and the
+// nonce. The whole header is authenticated along with the caller's associated data.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EnvelopeHeader {
+    pub suite: CipherSuite,
+    pub key_id: [u8; KEY_ID_LEN],
+    pub nonce: Vec<u8>,
+}
+
+impl EnvelopeHeader {
+    // Splits a versioned envelope into its header and ciphertext; None for unversioned payloads and unknown suites
+    pub fn parse(sealed: &[u8]) -> Option<(EnvelopeHeader, &[u8])> {
+        let rest = sealed.strip_prefix(&ENVELOPE_MAGIC)?;
+        let (&suite_id, rest) = rest.split_first()?;
+        let suite = CipherSuite::from_id(suite_id)?;
+        if rest.len() < KEY_ID_LEN + suite.nonce_len() {
+            return None;
+        }
+        let (key_id, rest) = rest.split_at(KEY_ID_LEN);
+        let (nonce, ciphertext) = rest.split_at(suite.nonce_len());
+        let header = EnvelopeHeader { suite, key_id: key_id.try_into().expect("split at the key id length"), nonce: nonce.to_vec() };
+        Some((header, ciphertext))
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = ENVELOPE_MAGIC.to_vec();
+        bytes.push(self.suite.id());
+        bytes.extend_from_slice(&self.key_id);
+        bytes.extend_from_slice(&self.nonce);
+        bytes
+    }
+
+    fn aad(&self, aad: &[u8]) -> Vec<u8> {
+        let mut bound = self.to_bytes();
+        bound.extend_from_slice(aad);
+        bound
+    }
+}
+
+// seal_envelope_with_rng: Encrypts under `suite` behind a versioned header naming the suite and the key
+pub fn seal_envelope_with_rng(rng: &mut impl CryptoRngCore, suite: CipherSuite, key: &[u8; 32], plaintext: &[u8], aad: &[u8]) -> Vec<u8> {
+    let mut nonce = alloc::vec![0u8; suite.nonce_len()];
+    rng.fill_bytes(&mut nonce);
+    let header = EnvelopeHeader { suite, key_id: key_id(key), nonce };
+    let ciphertext = match suite {
+        CipherSuite::XChaCha20Poly1305 => XChaCha20Poly1305::new(key.into())
+            .encrypt(XNonce::from_slice(&header.nonce), Payload { msg: plaintext, aad: &header.aad(aad) })
+            .expect("Failed to encrypt payload"),
+    };
+    let mut sealed = header.to_bytes();
+    sealed.extend(ciphertext);
+    sealed
+}
+
+// seal_envelope: seal_envelope_with_rng drawing the nonce from the operating system
+#[cfg(feature = "std")]
+pub fn seal_envelope(suite: CipherSuite, key: &[u8; 32], plaintext: &[u8], aad: &[u8]) -> Vec<u8> {
+    seal_envelope_with_rng(&mut rand_core::OsRng, suite, key, plaintext, aad)
+}
+
+// open_envelope: Opens a versioned envelope, or an unversioned payload from before envelopes existed. A legacy
+// payload's random nonce can start like a header, so anything that fails as an envelope is also tried as legacy.
+pub fn open_envelope(key: &[u8; 32], sealed: &[u8], aad: &[u8]) -> Option<Zeroizing<Vec<u8>>> {
+    let opened = EnvelopeHeader::parse(sealed)
+        .filter(|(header, _)| header.key_id == key_id(key))
+        .and_then(|(header, ciphertext)| match header.suite {
+            CipherSuite::XChaCha20Poly1305 => XChaCha20Poly1305::new(key.into())
+                .decrypt(XNonce::from_slice(&header.nonce), Payload { msg: ciphertext, aad: &header.aad(aad) })
+                .ok(),
+        });
+    match opened {
+        Some(plaintext) => Some(Zeroizing::new(plaintext)),
+        None => open_payload(key, sealed, aad),
+    }
+}
+
+// combine_agreements: Folds the X3DH Diffie-Hellman outputs, in protocol order, into one session secret
+pub fn combine_agreements(agreements: &[SharedSecret]) -> Zeroizing<[u8; 32]> {
+    let mut input = Zeroizing::new(Vec::with_capacity(agreements.len() * 32));
+    for agreement in agreements {
+        input.extend_from_slice(agreement.as_bytes());
+    }
+    let hkdf = Hkdf::<Sha3_256>::new(Some(b"cuneos-x3dh"), &input);
+    let mut session_secret = Zeroizing::new([0u8; 32]);
+    hkdf.expand(b"cuneos/session/v1", session_secret.as_mut())
+        .expect("32 bytes is a valid HKDF output length");
+    session_secret
+}
+
+// stealth_tag: Truncated hash of a stealth-address agreement; only the two ends of the agreement can compute it
+pub fn stealth_tag(agreement: &SharedSecret) -> [u8; 16] {
+    let mut hasher = Sha3_256::default();
+    hasher.update(b"cuneos/stealth-tag/v1");
+    hasher.update(agreement.as_bytes());
+    let digest = hasher.finalize();
+    let mut tag = [0u8; 16];
+    tag.copy_from_slice(&digest[..16]);
+    tag
+}
+
+// SignatureError: Why an Ed25519 signature check failed, so callers can report it in their own terms
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SignatureError {
+    MalformedKey,
+    MalformedSignature,
+    Invalid,
+}
+
+// SignatureParts: A signature decoded for checking against R = [s]B - [k]A, with k = SHA-512(R || A || payload).
+// A and R must be free of any small-order component and s must be canonical. On such points the cofactorless
+// equation holds exactly when the cofactored one does, so single and batch checks cannot disagree.
+struct SignatureParts {
+    public_key: EdwardsPoint,
+    r: EdwardsPoint,
+    s: Scalar,
+    challenge: Scalar,
+}
+
+impl SignatureParts {
+    fn decode(public_key: &[u8; 32], payload: &[u8], signature: &[u8]) -> Result<SignatureParts, SignatureError> {
+        let prime_order = |point: &EdwardsPoint| !point.is_small_order() && point.is_torsion_free();
+        let a = CompressedEdwardsY(*public_key).decompress().filter(prime_order).ok_or(SignatureError::MalformedKey)?;
+        let signature: &[u8; 64] = signature.try_into().map_err(|_| SignatureError::MalformedSignature)?;
+        let mut r_bytes = [0u8; 32];
+        r_bytes.copy_from_slice(&signature[..32]);
+        let mut s_bytes = [0u8; 32];
+        s_bytes.copy_from_slice(&signature[32..]);
+        let r = CompressedEdwardsY(r_bytes).decompress().filter(prime_order).ok_or(SignatureError::Invalid)?;
+        let s = Option::<Scalar>::from(Scalar::from_canonical_bytes(s_bytes)).ok_or(SignatureError::MalformedSignature)?;
+        let challenge = Scalar::from_hash(Sha512::new().chain_update(r_bytes).chain_update(public_key).chain_update(payload));
+        Ok(SignatureParts { public_key: a, r, s, challenge })
+    }
+}
+
+// verify_signature: Checks an Ed25519 signature over `payload` against raw public key bytes
+pub fn verify_signature(public_key: &[u8; 32], payload: &[u8], signature: &[u8]) -> Result<(), SignatureError> {
+    let parts = SignatureParts::decode(public_key, payload, signature)?;
+    let expected_r = EdwardsPoint::vartime_double_scalar_mul_basepoint(&-parts.challenge, &parts.public_key, &parts.s);
+    if expected_r == parts.r {
+        Ok(())
+    } else {
+        Err(SignatureError::Invalid)
+    }
+}
+
+// SignedPayload: One signature to check as part of a batch
+pub struct SignedPayload<'a> {
+    pub public_key: &'a [u8; 32],
+    pub payload: &'a [u8],
+    pub signature: &'a [u8],
+}
+
+// verify_batch_with_rng: Checks many Ed25519 signatures with one multiscalar multiplication, about twice as fast
+// as checking them one by one. Each signature is weighted by a random 128-bit scalar, so a forger cannot make
+// invalid signatures cancel out. The result says only whether every signature holds; callers that need to know
+// which one failed check them individually with verify_signature. Signatures are decoded and checked against
+// the same equation as verify_signature, so a batch holds exactly when each of its signatures would.
+pub fn verify_batch_with_rng(rng: &mut impl CryptoRngCore, items: &[SignedPayload<'_>]) -> Result<(), SignatureError> {
+    let mut base_coefficient = Scalar::ZERO;
+    let mut scalars = Vec::with_capacity(2 * items.len() + 1);
+    let mut points = Vec::with_capacity(2 * items.len() + 1);
+    for item in items {
+        let parts = SignatureParts::decode(item.public_key, item.payload, item.signature)?;
+        let mut weight_bytes = [0u8; 32];
+        rng.fill_bytes(&mut weight_bytes[..16]);
+        let weight = Scalar::from_bytes_mod_order(weight_bytes);
+        base_coefficient -= weight * parts.s;
+        scalars.push(weight);
+        points.push(parts.r);
+        scalars.push(weight * parts.challenge);
+        points.push(parts.public_key);
+    }
+    scalars.push(base_coefficient);
+    points.push(ED25519_BASEPOINT_POINT);
+    let sum = EdwardsPoint::vartime_multiscalar_mul(scalars, points);
+    if sum.is_identity() {
+        Ok(())
+    } else {
+        Err(SignatureError::Invalid)
+    }
+}
+
+// verify_batch: verify_batch_with_rng drawing the weights from the operating system
+#[cfg(feature = "std")]
+pub fn verify_batch(items: &[SignedPayload<'_>]) -> Result<(), SignatureError> {
+    verify_batch_with_rng(&mut rand_core::OsRng, items)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use curve25519_dalek::constants::EIGHT_TORSION;
+    use ed25519_dalek::{Signer, SigningKey};
+    use rand_core::{OsRng, RngCore};
+
+    fn random_scalar() -> Scalar {
+        let mut wide = [0u8; 64];
+        OsRng.fill_bytes(&mut wide);
+        Scalar::from_bytes_mod_order_wide(&wide)
+    }
+
+    // Signs with an order-8 point added to R and s computed for the resulting challenge, so R + [k]A - [s]B is
+    // that point: the cofactored equation holds and the cofactorless one does not
+    fn signature_with_torsion_in_r(payload: &[u8]) -> ([u8; 32], [u8; 64]) {
+        let (secret, nonce) = (random_scalar(), random_scalar());
+        let public_key = (ED25519_BASEPOINT_POINT * secret).compress().to_bytes();
+        let r = (ED25519_BASEPOINT_POINT * nonce + EIGHT_TORSION[1]).compress().to_bytes();
+        let challenge = Scalar::from_hash(Sha512::new().chain_update(r).chain_update(public_key).chain_update(payload));
+        let mut signature = [0u8; 64];
+        signature[..32].copy_from_slice(&r);
+        signature[32..].copy_from_slice((nonce + challenge * secret).as_bytes());
+        (public_key, signature)
+    }
+
+    #[test]
+    fn single_and_batch_checks_agree_on_signatures_with_small_order_components() {
+        let payload = b"cuneos/batch-agreement";
+        let signing_key = SigningKey::from_bytes(&[7u8; 32]);
+        let (honest_key, honest_signature) = (signing_key.verifying_key().to_bytes(), signing_key.sign(payload).to_bytes());
+        let (torsion_key, torsion_signature) = signature_with_torsion_in_r(payload);
+        // A small-order key and R with s = 0 satisfy the cofactored equation for any payload
+        let small_order = EIGHT_TORSION[1].compress().to_bytes();
+        let mut small_order_signature = [0u8; 64];
+        small_order_signature[..32].copy_from_slice(&small_order);
+
+        assert_eq!(verify_signature(&honest_key, payload, &honest_signature), Ok(()));
+        let cases: [(&[u8; 32], &[u8]); 3] = [(&honest_key, &honest_signature), (&torsion_key, &torsion_signature), (&small_order, &small_order_signature)];
+        for (public_key, signature) in cases {
+            let single = verify_signature(public_key, payload, signature);
+            // The batch weights are random, so a disagreement could hide behind a lucky draw; try several
+            for _ in 0..16 {
+                assert_eq!(verify_batch(&[SignedPayload { public_key, payload, signature }]), single);
+            }
+        }
+        assert!(verify_signature(&torsion_key, payload, &torsion_signature).is_err());
+        assert!(verify_signature(&small_order, payload, &small_order_signature).is_err());
+    }
+}